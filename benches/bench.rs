@@ -7,10 +7,10 @@ extern crate rand;
 const RAND_BENCH_N: u64 = 1000;
 
 use test::{black_box, Bencher};
-use regex_generate::{DEFAULT_MAX_REPEAT, Generator};
+use regex_generate::{DEFAULT_MAX_BUDGET, DEFAULT_MAX_REPEAT, Generator};
 
 fn test_generate(raw: &str, b: &mut Bencher) {
-    let mut g = Generator::new(raw, rand::thread_rng(), DEFAULT_MAX_REPEAT).unwrap();
+    let mut g = Generator::new(raw, rand::thread_rng(), DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
     let mut buffer = vec![];
 
     b.iter(move || {
@@ -1,19 +1,20 @@
 extern crate regex_generate;
 extern crate rand;
 
-use regex_generate::{DEFAULT_MAX_REPEAT, Generator};
+use regex_generate::{DEFAULT_MAX_BUDGET, DEFAULT_MAX_REPEAT, EncodedString};
 
 fn main() {
-    let mut gen = Generator::new(r"(?x)
+    let dist = EncodedString::new(r"(?x)
 (?P<year>[0-9]{4})  # the year
 -
 (?P<month>[0-9]{2}) # the month
 -
 (?P<day>[0-9]{2})   # the day
-", rand::thread_rng(), DEFAULT_MAX_REPEAT).unwrap();
-    let mut buffer = vec![];
-    gen.generate(&mut buffer).unwrap();
-    let output = String::from_utf8(buffer).unwrap();
+", DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+    let result = dist.generate_captures(&mut rand::thread_rng());
 
-    println!("Random Date: {}", output);
-}
\ No newline at end of file
+    println!("Random Date: {}", result.as_str());
+    println!("  year:  {}", result.name("year").unwrap());
+    println!("  month: {}", result.name("month").unwrap());
+    println!("  day:   {}", result.name("day").unwrap());
+}
@@ -0,0 +1,354 @@
+//! A regular expression's `Hir`, pre-compiled once into a form with nothing left to
+//! derive at sample time. In particular every character class's ranges are folded
+//! into a `ClassTable` up front, rather than being re-walked on every draw.
+
+use std::collections::HashMap;
+use std::io;
+use rand::Rng;
+use rand::distributions::uniform::Uniform;
+use rand::seq::SliceRandom;
+use regex_syntax::hir::{self, Hir, HirKind};
+
+use errors::*;
+use {repetition_range, Budget};
+
+/// A character class's ranges, precompiled once into a prefix-sum table of
+/// cumulative cardinalities, so sampling an element is an O(log ranges) binary
+/// search instead of the old "pick a range, then an element within it" scheme, which
+/// over-sampled characters in small ranges (e.g. giving `_` in `\w` the same weight
+/// as an entire letter range).
+pub(crate) struct ClassTable {
+    ranges: Vec<(u32, u32)>,
+    // prefix[i] is the total cardinality of ranges[..i]; the last entry is the
+    // class's total cardinality.
+    prefix: Vec<u64>,
+}
+
+impl ClassTable {
+    fn compile<I: IntoIterator<Item = (u32, u32)>>(ranges: I) -> ClassTable {
+        let mut out_ranges = Vec::new();
+        let mut prefix = vec![0u64];
+        let mut total = 0u64;
+        for (start, end) in ranges {
+            total += u64::from(end - start) + 1;
+            out_ranges.push((start, end));
+            prefix.push(total);
+        }
+        ClassTable { ranges: out_ranges, prefix: prefix }
+    }
+
+    fn unicode(ranges: &[hir::ClassUnicodeRange]) -> ClassTable {
+        ClassTable::compile(ranges.iter().map(|r| (u32::from(r.start()), u32::from(r.end()))))
+    }
+
+    fn bytes(ranges: &[hir::ClassBytesRange]) -> ClassTable {
+        ClassTable::compile(ranges.iter().map(|r| (u32::from(r.start()), u32::from(r.end()))))
+    }
+
+    fn sample_raw<R: Rng + ?Sized>(&self, rng: &mut R) -> u32 {
+        let total = *self.prefix.last().expect("class has at least one range");
+        let index = rng.gen_range(0..total);
+        let range_index = self.prefix.binary_search(&index).unwrap_or_else(|i| i - 1);
+        let (start, _) = self.ranges[range_index];
+        start + (index - self.prefix[range_index]) as u32
+    }
+
+    /// Sample a codepoint, retrying if it lands in the UTF-16 surrogate gap (a
+    /// class's ranges may still span it, e.g. `.` is `[\u{0}-\u{9}\u{b}-\u{10ffff}]`).
+    fn sample_char<R: Rng + ?Sized>(&self, rng: &mut R) -> char {
+        loop {
+            if let Some(c) = std::char::from_u32(self.sample_raw(rng)) {
+                return c;
+            }
+        }
+    }
+
+    fn sample_byte<R: Rng + ?Sized>(&self, rng: &mut R) -> u8 {
+        self.sample_raw(rng) as u8
+    }
+
+    /// The class's lowest codepoint, skipping the surrogate gap the same way
+    /// `sample_char` does.
+    fn min_char(&self) -> char {
+        let mut val = self.ranges[0].0;
+        loop {
+            if let Some(c) = std::char::from_u32(val) {
+                return c;
+            }
+            val += 1;
+        }
+    }
+
+    /// The class's lowest byte value.
+    fn min_byte(&self) -> u8 {
+        self.ranges[0].0 as u8
+    }
+}
+
+/// A compiled `Hir`, mirroring its shape one-to-one except that `Class` leaves carry
+/// a precomputed `ClassTable` in place of raw ranges.
+pub(crate) enum Compiled {
+    EmptyMatch,
+    EndLine,
+    LiteralUnicode(char),
+    LiteralByte(u8),
+    ClassUnicode(ClassTable),
+    ClassBytes(ClassTable),
+    Group(hir::GroupKind, Box<Compiled>),
+    Concat(Vec<Compiled>),
+    Alternation(Vec<Compiled>),
+    Repetition { kind: hir::RepetitionKind, greedy: bool, hir: Box<Compiled> },
+}
+
+impl Compiled {
+    pub(crate) fn compile(hir: &Hir) -> Compiled {
+        match *hir.kind() {
+            HirKind::Anchor(hir::Anchor::EndLine) => Compiled::EndLine,
+            HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => Compiled::EmptyMatch,
+            HirKind::Literal(hir::Literal::Unicode(c)) => Compiled::LiteralUnicode(c),
+            HirKind::Literal(hir::Literal::Byte(b)) => Compiled::LiteralByte(b),
+            HirKind::Class(hir::Class::Unicode(ref class)) => {
+                Compiled::ClassUnicode(ClassTable::unicode(class.ranges()))
+            }
+            HirKind::Class(hir::Class::Bytes(ref class)) => {
+                Compiled::ClassBytes(ClassTable::bytes(class.ranges()))
+            }
+            HirKind::Group(ref group) => {
+                Compiled::Group(group.kind.clone(), Box::new(Compiled::compile(&group.hir)))
+            }
+            HirKind::Concat(ref hirs) => {
+                Compiled::Concat(hirs.iter().map(Compiled::compile).collect())
+            }
+            HirKind::Alternation(ref hirs) => {
+                Compiled::Alternation(hirs.iter().map(Compiled::compile).collect())
+            }
+            HirKind::Repetition(ref repetition) => Compiled::Repetition {
+                kind: repetition.kind.clone(),
+                greedy: repetition.greedy,
+                hir: Box::new(Compiled::compile(&repetition.hir)),
+            },
+        }
+    }
+}
+
+fn write_char<W: io::Write>(c: char, buffer: &mut W, budget: &mut Budget) -> io::Result<()> {
+    let mut b = [0; 4];
+    let sl = c.encode_utf8(&mut b).len();
+    buffer.write(&b[0..sl])?;
+    budget.spend(sl as u64);
+    Ok(())
+}
+
+/// Clamp a sampled repeat `count` to what `budget` allows, never dropping below `min`
+/// so the output still matches the expression.
+fn clamp_to_budget(count: u32, min: u32, budget: &Budget) -> u32 {
+    let extra_allowed = budget.remaining().min(u64::from(u32::max_value())) as u32;
+    count.min(min.saturating_add(extra_allowed))
+}
+
+pub(crate) fn generate<W: io::Write, R: Rng + ?Sized>(
+    buffer: &mut W,
+    compiled: &Compiled,
+    rng: &mut R,
+    max_repeat: u32,
+    budget: &mut Budget,
+) -> Result<()> {
+    match *compiled {
+        Compiled::EndLine => {
+            buffer.write(b"\n").chain_err(|| "failed to write end of line")?;
+            budget.spend(1);
+            Ok(())
+        }
+        Compiled::EmptyMatch => Ok(()),
+        Compiled::LiteralUnicode(c) => write_char(c, buffer, budget).chain_err(|| "failed to write literal value"),
+        Compiled::LiteralByte(b) => {
+            buffer.write(&[b]).chain_err(|| "failed to write literal value")?;
+            budget.spend(1);
+            Ok(())
+        }
+        Compiled::ClassUnicode(ref table) => {
+            write_char(table.sample_char(rng), buffer, budget).chain_err(|| "failed to write class")
+        }
+        Compiled::ClassBytes(ref table) => {
+            buffer.write(&[table.sample_byte(rng)]).chain_err(|| "failed to write class")?;
+            budget.spend(1);
+            Ok(())
+        }
+        Compiled::Repetition { ref kind, greedy, ref hir } => {
+            let range = repetition_range(kind, max_repeat);
+            let count = if greedy {
+                rng.sample(Uniform::new_inclusive(range.0, range.1))
+            } else {
+                range.0
+            };
+            let count = clamp_to_budget(count, range.0, budget);
+            for _ in 0..count {
+                generate(buffer, hir, rng, max_repeat, budget).expect("Fail");
+            }
+            Ok(())
+        }
+        Compiled::Group(_, ref hir) => generate(buffer, hir, rng, max_repeat, budget),
+        Compiled::Concat(ref items) => {
+            for item in items {
+                generate(buffer, item, rng, max_repeat, budget).expect("Fail");
+            }
+            Ok(())
+        }
+        Compiled::Alternation(ref items) => {
+            let item = items.choose(rng).expect("non empty alternations");
+            generate(buffer, item, rng, max_repeat, budget)
+        }
+    }
+}
+
+/// Like `generate`, but also records the buffer range written by every capturing
+/// `Group`. Specialized to `Vec<u8>` (rather than a generic `io::Write`) so it can
+/// read back `buffer.len()` before and after generating each group's contents.
+pub(crate) fn generate_captures<R: Rng + ?Sized>(
+    buffer: &mut Vec<u8>,
+    compiled: &Compiled,
+    rng: &mut R,
+    max_repeat: u32,
+    budget: &mut Budget,
+    indexed: &mut HashMap<u32, (usize, usize)>,
+    named: &mut HashMap<String, u32>,
+) {
+    match *compiled {
+        Compiled::EndLine => {
+            buffer.extend_from_slice(b"\n");
+            budget.spend(1);
+        }
+        Compiled::EmptyMatch => {}
+        Compiled::LiteralUnicode(c) => {
+            write_char(c, buffer, budget).expect("writing to a Vec<u8> cannot fail");
+        }
+        Compiled::LiteralByte(b) => {
+            buffer.push(b);
+            budget.spend(1);
+        }
+        Compiled::ClassUnicode(ref table) => {
+            write_char(table.sample_char(rng), buffer, budget).expect("writing to a Vec<u8> cannot fail");
+        }
+        Compiled::ClassBytes(ref table) => {
+            buffer.push(table.sample_byte(rng));
+            budget.spend(1);
+        }
+        Compiled::Repetition { ref kind, greedy, ref hir } => {
+            let range = repetition_range(kind, max_repeat);
+            let count = if greedy {
+                rng.sample(Uniform::new_inclusive(range.0, range.1))
+            } else {
+                range.0
+            };
+            let count = clamp_to_budget(count, range.0, budget);
+            for _ in 0..count {
+                generate_captures(buffer, hir, rng, max_repeat, budget, indexed, named);
+            }
+        }
+        Compiled::Group(ref kind, ref hir) => {
+            let start = buffer.len();
+            generate_captures(buffer, hir, rng, max_repeat, budget, indexed, named);
+            let end = buffer.len();
+            match *kind {
+                hir::GroupKind::CaptureIndex(index) => {
+                    indexed.insert(index, (start, end));
+                }
+                hir::GroupKind::CaptureName { ref name, index } => {
+                    indexed.insert(index, (start, end));
+                    named.insert(name.clone(), index);
+                }
+                hir::GroupKind::NonCapturing => {}
+            }
+        }
+        Compiled::Concat(ref items) => {
+            for item in items {
+                generate_captures(buffer, item, rng, max_repeat, budget, indexed, named);
+            }
+        }
+        Compiled::Alternation(ref items) => {
+            let item = items.choose(rng).expect("non empty alternations");
+            generate_captures(buffer, item, rng, max_repeat, budget, indexed, named);
+        }
+    }
+}
+
+/// The length, in bytes, of the shortest value `compiled` can generate, used by
+/// `generate_minimal` to pick an `Alternation`'s shortest branch.
+fn min_len(compiled: &Compiled, max_repeat: u32) -> u64 {
+    match *compiled {
+        Compiled::EndLine => 1,
+        Compiled::EmptyMatch => 0,
+        Compiled::LiteralUnicode(c) => c.len_utf8() as u64,
+        Compiled::LiteralByte(_) => 1,
+        Compiled::ClassUnicode(ref table) => table.min_char().len_utf8() as u64,
+        Compiled::ClassBytes(_) => 1,
+        Compiled::Group(_, ref hir) => min_len(hir, max_repeat),
+        Compiled::Concat(ref items) => items.iter().map(|item| min_len(item, max_repeat)).sum(),
+        Compiled::Alternation(ref items) => items
+            .iter()
+            .map(|item| min_len(item, max_repeat))
+            .min()
+            .expect("non empty alternations"),
+        Compiled::Repetition { ref kind, ref hir, .. } => {
+            let (min, _) = repetition_range(kind, max_repeat);
+            u64::from(min) * min_len(hir, max_repeat)
+        }
+    }
+}
+
+/// Like `generate`, but deterministic and RNG-free: every `Repetition` takes its
+/// minimum count, every `Alternation` picks the branch with the shortest output, and
+/// every `Class` picks its lowest code point/byte. Produces the shortest string the
+/// expression can match, useful as a canonical representative for regression fixtures.
+pub(crate) fn generate_minimal<W: io::Write>(
+    buffer: &mut W,
+    compiled: &Compiled,
+    max_repeat: u32,
+    budget: &mut Budget,
+) -> Result<()> {
+    match *compiled {
+        Compiled::EndLine => {
+            buffer.write(b"\n").chain_err(|| "failed to write end of line")?;
+            budget.spend(1);
+            Ok(())
+        }
+        Compiled::EmptyMatch => Ok(()),
+        Compiled::LiteralUnicode(c) => write_char(c, buffer, budget).chain_err(|| "failed to write literal value"),
+        Compiled::LiteralByte(b) => {
+            buffer.write(&[b]).chain_err(|| "failed to write literal value")?;
+            budget.spend(1);
+            Ok(())
+        }
+        Compiled::ClassUnicode(ref table) => {
+            write_char(table.min_char(), buffer, budget).chain_err(|| "failed to write class")
+        }
+        Compiled::ClassBytes(ref table) => {
+            buffer.write(&[table.min_byte()]).chain_err(|| "failed to write class")?;
+            budget.spend(1);
+            Ok(())
+        }
+        Compiled::Repetition { ref kind, ref hir, .. } => {
+            // Minimal mode already takes the lowest count a repetition allows, so
+            // there's nothing left for the budget to clamp.
+            let (min, _) = repetition_range(kind, max_repeat);
+            for _ in 0..min {
+                generate_minimal(buffer, hir, max_repeat, budget).expect("Fail");
+            }
+            Ok(())
+        }
+        Compiled::Group(_, ref hir) => generate_minimal(buffer, hir, max_repeat, budget),
+        Compiled::Concat(ref items) => {
+            for item in items {
+                generate_minimal(buffer, item, max_repeat, budget).expect("Fail");
+            }
+            Ok(())
+        }
+        Compiled::Alternation(ref items) => {
+            let item = items
+                .iter()
+                .min_by_key(|item| min_len(item, max_repeat))
+                .expect("non empty alternations");
+            generate_minimal(buffer, item, max_repeat, budget)
+        }
+    }
+}
@@ -4,175 +4,210 @@
 extern crate rand;
 extern crate regex_syntax;
 
+mod compiled;
 mod errors;
+mod uniform;
 
+pub use uniform::UniformGenerator;
+
+use compiled::Compiled;
 use errors::*;
+use std::collections::HashMap;
 use std::io;
-use std::ops::{Add, Sub, AddAssign};
 use rand::Rng;
-use rand::distributions::uniform::{Uniform, SampleUniform};
-use rand::seq::SliceRandom;
-use regex_syntax::hir::{self, Hir, HirKind};
+use rand::distributions::Distribution;
+use regex_syntax::hir;
 use regex_syntax::Parser;
 
 pub const DEFAULT_MAX_REPEAT: u32 = 100;
 
-/// Generator reads a string of regular expression syntax and generates strings based on it.
-pub struct Generator<R: Rng> {
-    hir: Hir,
-    rng: R,
+/// Default total output-size budget (see `Budget`), generous enough that it is never
+/// hit by a reasonably-sized expression.
+pub const DEFAULT_MAX_BUDGET: u64 = 1 << 20;
+
+/// A shared, mutable counter of remaining output bytes, threaded through generation to
+/// bound combinatorial blowup from nested unbounded repetitions (e.g. `(.*)*`). Each
+/// `Repetition` clamps how far beyond its minimum count it samples to what's left, and
+/// falls back to that minimum once the budget is exhausted.
+pub struct Budget(u64);
+
+impl Budget {
+    /// Create a budget of `units` output bytes.
+    pub fn new(units: u64) -> Budget {
+        Budget(units)
+    }
+
+    fn remaining(&self) -> u64 {
+        self.0
+    }
+
+    fn spend(&mut self, units: u64) {
+        self.0 = self.0.saturating_sub(units);
+    }
+}
+
+/// A regular expression compiled once, independent of any `Rng`, ready to be sampled
+/// from via `rand`'s `Distribution` trait (as a `String` or as raw `Vec<u8>`).
+///
+/// Compiling once and sampling many times lets a single `EncodedString` be shared
+/// across threads, each with its own `Rng`, and composes with the rest of the `rand`
+/// ecosystem (e.g. `rng.sample_iter(&dist)`).
+pub struct EncodedString {
+    compiled: Compiled,
     max_repeat: u32,
+    max_budget: u64,
 }
 
-impl<R: Rng> Generator<R> {
-    /// Create a new Generator from the regular expression string and use the given Rng for randomization.
-    pub fn parse(s: &str, rng: R) -> Result<Generator<R>> {
-        Self::new(s, rng, DEFAULT_MAX_REPEAT)
+impl EncodedString {
+    /// Compile a regular expression string for sampling.
+    pub fn parse(s: &str) -> Result<EncodedString> {
+        Self::new(s, DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET)
     }
 
-    /// Create a new Generator from the regular expression string and use the given Rng for randomization
-    /// with a maximum limit on repititions of the given amount.
-    pub fn new(s: &str, rng: R, max_repeat: u32) -> Result<Generator<R>> {
+    /// Compile a regular expression string for sampling, with a maximum limit on
+    /// repetitions of the given amount and a total output-size budget (see `Budget`)
+    /// applied to every generated value.
+    pub fn new(s: &str, max_repeat: u32, max_budget: u64) -> Result<EncodedString> {
         let hir = Parser::new().parse(s).chain_err(|| "could not parse expression")?;
-        Ok(Generator {
-            hir: hir,
-            rng: rng,
+        Ok(EncodedString {
+            compiled: Compiled::compile(&hir),
             max_repeat: max_repeat,
+            max_budget: max_budget,
         })
     }
 
-    /// Fill the provided buffer with values randomly derived from the regular expression
-    pub fn generate<W:io::Write>(&mut self, buffer: &mut W) -> Result<()> {
-        Self::generate_from_hir(buffer, &self.hir, &mut self.rng, self.max_repeat)
+    /// Generate a value, returning it alongside the generated substring of every
+    /// capture group (`(...)` or `(?P<name>...)`) in the expression.
+    pub fn generate_captures<R: Rng + ?Sized>(&self, rng: &mut R) -> GeneratedMatch {
+        let mut buffer = Vec::new();
+        let mut budget = Budget::new(self.max_budget);
+        let mut indexed = HashMap::new();
+        let mut named = HashMap::new();
+        compiled::generate_captures(&mut buffer, &self.compiled, rng, self.max_repeat, &mut budget, &mut indexed, &mut named);
+        GeneratedMatch { buffer: buffer, indexed: indexed, named: named }
+    }
+
+    /// Generate the shortest value the expression can match, deterministically and
+    /// without needing an `Rng`: every `Repetition` takes its minimum count, every
+    /// `Alternation` picks its shortest branch, and every `Class` picks its lowest
+    /// code point/byte. Useful as a canonical representative of a pattern, e.g. for
+    /// regression fixtures.
+    pub fn generate_minimal(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut budget = Budget::new(self.max_budget);
+        compiled::generate_minimal(&mut buffer, &self.compiled, self.max_repeat, &mut budget)
+            .expect("writing to a Vec<u8> cannot fail");
+        buffer
     }
+}
 
-    fn generate_from_hir<W:io::Write>(buffer: &mut W, hir: &Hir, rng: &mut R, max_repeat: u32) -> Result<()> {
-        fn write_char<W: io::Write>(c:char, buffer: &mut W) -> io::Result<()> {
-            let mut b = [0; 4];
-            let sl = c.encode_utf8(&mut b).len();
-            buffer.write(&b[0..sl])?;
-            Ok(())
-        }
+/// A generated value together with the generated substring of each capture group in
+/// the expression that produced it.
+pub struct GeneratedMatch {
+    buffer: Vec<u8>,
+    indexed: HashMap<u32, (usize, usize)>,
+    named: HashMap<String, u32>,
+}
 
-        match *hir.kind() {
-            HirKind::Anchor(hir::Anchor::EndLine) => {
-                buffer.write(b"\n").chain_err(|| "failed to write end of line")?;
-                Ok(())
-            }
-            HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => {
-                Ok(())
-            }
-            HirKind::Literal(hir::Literal::Unicode(c)) => {
-                write_char(c, buffer).chain_err(|| "failed to write literal value")
-            }
-            HirKind::Literal(hir::Literal::Byte(b)) => {
-                buffer.write(&[b]).chain_err(|| "failed to write literal value")?;
-                Ok(())
-            }
-            HirKind::Class(hir::Class::Unicode(ref class)) => {
-                loop {
-                    let val = sample_from_ranges(class.ranges(), rng);
-                    if let Some(c) = std::char::from_u32(val) {
-                        return write_char(c, buffer).chain_err(|| "failed to write class");
-                    }
-                }
-            }
-            HirKind::Class(hir::Class::Bytes(ref class)) => {
-                let b = sample_from_ranges(class.ranges(), rng) as u8;
-                buffer.write(&[b]).chain_err(|| "failed to write class")?;
-                Ok(())
-            }
-            HirKind::Repetition(ref repetition) => {
-                let limit = max_repeat - 1;
-                let range = match repetition.kind {
-                    hir::RepetitionKind::ZeroOrOne => (0, 1),
-                    hir::RepetitionKind::ZeroOrMore => (0, limit),
-                    hir::RepetitionKind::OneOrMore => (1, limit),
-                    hir::RepetitionKind::Range(ref r) => match *r {
-                        hir::RepetitionRange::Exactly(n) => (n, n),
-                        hir::RepetitionRange::AtLeast(n) => (n, limit),
-                        hir::RepetitionRange::Bounded(m, n) => (m, n),
-                    },
-                };
-                let count = if repetition.greedy {
-                    rng.sample(Uniform::new_inclusive(range.0, range.1))
-                } else {
-                    range.0
-                };
-                for _ in 0..count {
-                    Self::generate_from_hir(buffer, &repetition.hir, rng, max_repeat).expect("Fail");
-                }
-                Ok(())
-            }
-            HirKind::Group(ref group) => {
-                Self::generate_from_hir(buffer, &group.hir, rng, max_repeat)
-            }
-            HirKind::Concat(ref hirs) => {
-                for h in hirs {
-                    Self::generate_from_hir(buffer, h, rng, max_repeat).expect("Fail");
-                }
-                Ok(())
-            }
-            HirKind::Alternation(ref hirs) => {
-                let h = hirs.choose(rng).expect("non empty alternations");
-                Self::generate_from_hir(buffer, h, rng, max_repeat)
-            }
-        }
+impl GeneratedMatch {
+    /// The full generated value. Panics if it isn't valid UTF-8 (see `EncodedString`'s
+    /// `Distribution<String>` impl for when that can happen).
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.buffer).expect("generated bytes were not valid UTF-8")
+    }
+
+    /// The generated substring of capture group `index` (1-based, matching the
+    /// indices `regex` itself assigns), or `None` if the expression has no such group
+    /// or the group was not generated (e.g. it sits in an alternation branch that was
+    /// not chosen, or in a repetition that ran zero times).
+    pub fn get(&self, index: u32) -> Option<&str> {
+        let (start, end) = *self.indexed.get(&index)?;
+        Some(&self.as_str()[start..end])
+    }
+
+    /// The generated substring of the named capture group `(?P<name>...)`, or `None`
+    /// if the expression has no such group or the group was not generated.
+    pub fn name(&self, name: &str) -> Option<&str> {
+        let index = self.named.get(name)?;
+        self.get(*index)
     }
 }
 
-trait Interval {
-    type Item: SampleUniform
-        + Add<Output = Self::Item>
-        + Sub<Output = Self::Item>
-        + AddAssign
-        + From<u8>
-        + Copy
-        + Ord;
-    fn bounds(&self) -> (Self::Item, Self::Item);
+impl Distribution<Vec<u8>> for EncodedString {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut budget = Budget::new(self.max_budget);
+        compiled::generate(&mut buffer, &self.compiled, rng, self.max_repeat, &mut budget)
+            .expect("writing to a Vec<u8> cannot fail");
+        buffer
+    }
 }
 
-impl Interval for hir::ClassUnicodeRange {
-    type Item = u32;
-    fn bounds(&self) -> (Self::Item, Self::Item) { (self.start().into(), self.end().into()) }
+impl Distribution<String> for EncodedString {
+    /// Sample a `String`. Panics if the expression generated bytes that aren't valid
+    /// UTF-8, which can only happen if it contains byte literals or byte classes
+    /// (e.g. via the `(?-u)` flag) that fall outside ASCII.
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> String {
+        let bytes: Vec<u8> = self.sample(rng);
+        String::from_utf8(bytes).expect("generated bytes were not valid UTF-8")
+    }
 }
 
-impl Interval for hir::ClassBytesRange {
-    type Item = u8;
-    fn bounds(&self) -> (Self::Item, Self::Item) { (self.start(), self.end()) }
+/// Generator reads a string of regular expression syntax and generates strings based on it.
+///
+/// This is a thin wrapper around `EncodedString` that owns its own `Rng`; use
+/// `EncodedString` directly to share one compiled regular expression across threads
+/// or to use it anywhere a `Distribution` is expected.
+pub struct Generator<R: Rng> {
+    dist: EncodedString,
+    rng: R,
 }
 
-const SAMPLE_UNBIASED_LIMIT: usize = 2;
-
-fn sample_from_ranges<I: Interval, R: Rng>(ranges: &[I], rng: &mut R) -> I::Item {
-    if ranges.len() <= SAMPLE_UNBIASED_LIMIT {
-        // We use unbiased sampling when number of ranges is small.
-        // In particular this includes the case of `.` (AnyCharNoNL),
-        // which is equivalent to `[\u{0}-\u{9}\u{b}-\u{10ffff}]`.
-        // Using the biased sample will give \u{0}-\u{9} 50% of the time and is unrealistic.
-
-        let zero = I::Item::from(0);
-        let mut normalized_ranges = [(zero, zero); SAMPLE_UNBIASED_LIMIT];
-        let mut normalized_len = zero;
-        for (i, r) in ranges.iter().enumerate() {
-            let (start, end) = r.bounds();
-            normalized_ranges[i] = (normalized_len, start);
-            normalized_len += end - start + I::Item::from(1);
-        }
+impl<R: Rng> Generator<R> {
+    /// Create a new Generator from the regular expression string and use the given Rng for randomization.
+    pub fn parse(s: &str, rng: R) -> Result<Generator<R>> {
+        Self::new(s, rng, DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET)
+    }
+
+    /// Create a new Generator from the regular expression string and use the given Rng for randomization,
+    /// with a maximum limit on repititions of the given amount and a total output-size budget (see
+    /// `Budget`) applied to every generated value.
+    pub fn new(s: &str, rng: R, max_repeat: u32, max_budget: u64) -> Result<Generator<R>> {
+        let dist = EncodedString::new(s, max_repeat, max_budget)?;
+        Ok(Generator {
+            dist: dist,
+            rng: rng,
+        })
+    }
 
-        let normalized_index = rng.gen_range(zero..normalized_len);
-        let range_index = normalized_ranges[..ranges.len()]
-            .binary_search_by(|&(ns, _)| ns.cmp(&normalized_index))
-            .unwrap_or_else(|i| i - 1);
-        let (normalized_start, start) = normalized_ranges[range_index];
+    /// Fill the provided buffer with values randomly derived from the regular expression
+    pub fn generate<W:io::Write>(&mut self, buffer: &mut W) -> Result<()> {
+        let mut budget = Budget::new(self.dist.max_budget);
+        compiled::generate(buffer, &self.dist.compiled, &mut self.rng, self.dist.max_repeat, &mut budget)
+    }
 
-        normalized_index - normalized_start + start
+    /// Fill the provided buffer with the shortest value the regular expression can
+    /// match, deterministically (see `EncodedString::generate_minimal`). Does not use
+    /// this generator's `Rng`.
+    pub fn generate_minimal<W: io::Write>(&mut self, buffer: &mut W) -> Result<()> {
+        let mut budget = Budget::new(self.dist.max_budget);
+        compiled::generate_minimal(buffer, &self.dist.compiled, self.dist.max_repeat, &mut budget)
+    }
+}
 
-    } else {
-        // We use biased sampling otherwise due to speed concern.
-        let range = ranges.choose(rng).expect("at least one range in the class");
-        let (start, end) = range.bounds();
-        rng.sample(Uniform::new_inclusive(start, end))
+/// Resolve a repetition's `(min, max)` repeat bounds, capping unbounded repetitions
+/// (`*`, `+`, `{n,}`) at `max_repeat - 1`.
+pub(crate) fn repetition_range(kind: &hir::RepetitionKind, max_repeat: u32) -> (u32, u32) {
+    let limit = max_repeat - 1;
+    match *kind {
+        hir::RepetitionKind::ZeroOrOne => (0, 1),
+        hir::RepetitionKind::ZeroOrMore => (0, limit),
+        hir::RepetitionKind::OneOrMore => (1, limit),
+        hir::RepetitionKind::Range(ref r) => match *r {
+            hir::RepetitionRange::Exactly(n) => (n, n),
+            hir::RepetitionRange::AtLeast(n) => (n, limit),
+            hir::RepetitionRange::Bounded(m, n) => (m, n),
+        },
     }
 }
 
@@ -180,14 +215,15 @@ fn sample_from_ranges<I: Interval, R: Rng>(ranges: &[I], rng: &mut R) -> I::Item
 mod tests {
     extern crate regex;
 
-    use super::{DEFAULT_MAX_REPEAT, Generator};
+    use super::{DEFAULT_MAX_BUDGET, DEFAULT_MAX_REPEAT, EncodedString, Generator};
     use self::regex::Regex;
     use rand;
+    use rand::distributions::Distribution;
 
     const TEST_N: u64 = 10000;
 
     fn test_regex(raw: &str) {
-        let mut gen = Generator::new(raw, rand::thread_rng(), DEFAULT_MAX_REPEAT).unwrap();
+        let mut gen = Generator::new(raw, rand::thread_rng(), DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
         // let expr = Expr::parse(raw).unwrap();
         let rx = Regex::new(raw).unwrap();
         // println!("Testing: {:?} against \\{:?}\\", gen, rx);
@@ -409,4 +445,127 @@ mod tests {
     fn gen_complex() {
         test_regex(r"^(\p{Greek}\P{Greek})(?:\d{3,6})$");
     }
+
+    #[test]
+    fn class_sampling_is_unbiased_across_range_sizes() {
+        // A class combining a 26-char range (`a-z`) with a single-char range (`_`):
+        // "pick a range, then an element within it" sampling would give `_` the same
+        // 50% weight as the entire `a-z` range put together, instead of its fair
+        // 1-in-27 share. Assert the observed frequency lands near its fair share.
+        const N: u64 = 20000;
+        let mut gen = Generator::new(r"[a-z_]", rand::thread_rng(), DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+        let mut underscores = 0u64;
+        let mut buffer = vec![];
+        for _ in 0..N {
+            gen.generate(&mut buffer).unwrap();
+            if buffer == b"_" {
+                underscores += 1;
+            }
+            buffer.clear();
+        }
+        let frequency = underscores as f64 / N as f64;
+        let expected = 1.0 / 27.0;
+        assert!(
+            (frequency - expected).abs() < 0.02,
+            "underscore frequency {} too far from expected {} (biased sampling?)",
+            frequency,
+            expected
+        );
+    }
+
+    #[test]
+    fn captures_named_and_numeric_agree() {
+        let dist = EncodedString::new(r"(?P<year>[0-9]{4})", DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+        let result = dist.generate_captures(&mut rand::thread_rng());
+        assert_eq!(result.get(1), result.name("year"));
+        assert!(result.name("year").unwrap().chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn captures_missing_name_and_index_are_none() {
+        let dist = EncodedString::new(r"(?P<year>[0-9]{4})", DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+        let result = dist.generate_captures(&mut rand::thread_rng());
+        assert_eq!(result.name("month"), None);
+        assert_eq!(result.get(2), None);
+    }
+
+    #[test]
+    fn captures_nested_in_repetition_keep_last_iteration() {
+        // A capture group inside a `Repetition` is overwritten on every iteration, so
+        // only the last iteration's range survives.
+        let dist = EncodedString::new(r"(?:(a|b|c))+", DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+        let result = dist.generate_captures(&mut rand::thread_rng());
+        let captured = result.get(1).expect("repeated group should still capture");
+        assert!(result.as_str().ends_with(captured));
+    }
+
+    #[test]
+    fn budget_bounds_nested_repetition_blowup() {
+        // `(.*)*` would otherwise apply `max_repeat` independently at each nesting
+        // level and multiply together. A repetition's count is clamped to the remaining
+        // budget *before* its children run, counting one budget byte per repeat, while
+        // each repeat of the `.` class can write up to 4 bytes (the widest UTF-8
+        // encoding); so the output can reach 4 times the budget, and that is the bound
+        // asserted here rather than the budget itself.
+        let max_budget = 64;
+        let slack = 4;
+        let dist = EncodedString::new(r"(.*)*", DEFAULT_MAX_REPEAT, max_budget).unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let bytes: Vec<u8> = dist.sample(&mut rng);
+            assert!(
+                bytes.len() as u64 <= max_budget * slack,
+                "budget did not bound output: got {} bytes for budget {}",
+                bytes.len(),
+                max_budget
+            );
+        }
+    }
+
+    #[test]
+    fn budget_exhausted_falls_back_to_repetition_minimum() {
+        // With no budget left at all, a `Repetition` must still emit its regex-mandated
+        // minimum count rather than clamping all the way down to zero.
+        let dist = EncodedString::new(r"a{5,10}", DEFAULT_MAX_REPEAT, 0).unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let bytes: Vec<u8> = dist.sample(&mut rng);
+            assert_eq!(bytes.len(), 5, "expected fallback to the repetition's minimum count");
+        }
+    }
+
+    #[test]
+    fn generate_minimal_picks_shortest_alternation_branch() {
+        let raw = r"abc|de|f";
+        let mut gen = Generator::new(raw, rand::thread_rng(), DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+        let mut buffer = vec![];
+        gen.generate_minimal(&mut buffer).unwrap();
+        let s = String::from_utf8(buffer).unwrap();
+        assert_eq!(s, "f");
+        assert!(Regex::new(raw).unwrap().is_match(&s));
+    }
+
+    #[test]
+    fn generate_minimal_uses_repetition_minimum_count() {
+        let raw = r"a{3,8}";
+        let mut gen = Generator::new(raw, rand::thread_rng(), DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+        let mut buffer = vec![];
+        gen.generate_minimal(&mut buffer).unwrap();
+        let s = String::from_utf8(buffer).unwrap();
+        assert_eq!(s, "aaa");
+        assert!(Regex::new(raw).unwrap().is_match(&s));
+    }
+
+    #[test]
+    fn generate_minimal_is_deterministic() {
+        let raw = r"(abc|de|f){2,4}";
+        let mut gen1 = Generator::new(raw, rand::thread_rng(), DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+        let mut gen2 = Generator::new(raw, rand::thread_rng(), DEFAULT_MAX_REPEAT, DEFAULT_MAX_BUDGET).unwrap();
+        let mut b1 = vec![];
+        let mut b2 = vec![];
+        gen1.generate_minimal(&mut b1).unwrap();
+        gen2.generate_minimal(&mut b2).unwrap();
+        assert_eq!(b1, b2);
+        assert!(Regex::new(raw).unwrap().is_match(&String::from_utf8(b1).unwrap()));
+    }
 }
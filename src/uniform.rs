@@ -0,0 +1,610 @@
+//! Uniform sampling over the language matched by a regular expression, bounded to a
+//! maximum output length.
+//!
+//! Unlike `Generator`, which makes an independent random choice at every `Hir` node,
+//! `UniformGenerator` precompiles per-node "how many strings of length N does this
+//! produce" tables and samples the overall length first, splitting it among children
+//! proportionally to those tables.
+
+use std::io;
+use rand::Rng;
+use regex_syntax::hir::{self, Hir, HirKind};
+use regex_syntax::Parser;
+
+use errors::*;
+use repetition_range;
+
+/// Number of strings of each byte length, from `0` to some bound, that a node can produce.
+/// `table[len]` is the count for exactly `len` bytes.
+type CountTable = Vec<u128>;
+
+fn zero_table(max_len: usize) -> CountTable {
+    vec![0; max_len + 1]
+}
+
+/// Discrete convolution of `a` and `b`, truncated to `max_len`.
+///
+/// Counts grow multiplicatively (a single-byte class of cardinality `c` repeated `k`
+/// times puts `c^k` in the table at the minimal length), which overflows `u128` for
+/// entirely realistic patterns, not just pathological ones. Checked arithmetic turns
+/// that into an error instead of a panic (debug) or a silently corrupt count (release).
+fn convolve(a: &CountTable, b: &CountTable, max_len: usize) -> Result<CountTable> {
+    let mut out = zero_table(max_len);
+    for (la, &ca) in a.iter().enumerate() {
+        if ca == 0 {
+            continue;
+        }
+        for (lb, &cb) in b.iter().enumerate() {
+            if la + lb > max_len {
+                break;
+            }
+            out[la + lb] = checked_add_counts(out[la + lb], checked_mul_counts(ca, cb)?)?;
+        }
+    }
+    Ok(out)
+}
+
+/// Multiply two table counts, erroring out rather than overflowing `u128`.
+fn checked_mul_counts(a: u128, b: u128) -> Result<u128> {
+    a.checked_mul(b)
+        .ok_or_else(|| "expression matches too many strings to count exactly (overflowed u128)".into())
+}
+
+/// Add two table counts, erroring out rather than overflowing `u128`.
+fn checked_add_counts(a: u128, b: u128) -> Result<u128> {
+    a.checked_add(b)
+        .ok_or_else(|| "expression matches too many strings to count exactly (overflowed u128)".into())
+}
+
+fn table_sum(table: &[u128]) -> u128 {
+    table.iter().sum()
+}
+
+/// Draw an index in `[0, weights.sum())` and return the position whose cumulative
+/// weight first covers it. Panics if every weight is zero, which callers must rule
+/// out (the caller should only reach here when the corresponding table entry is known
+/// to be nonzero).
+fn weighted_index<R: Rng>(weights: &[u128], rng: &mut R) -> usize {
+    let total = table_sum(weights);
+    let mut idx = rng.gen_range(0u128..total);
+    for (i, &w) in weights.iter().enumerate() {
+        if idx < w {
+            return i;
+        }
+        idx -= w;
+    }
+    unreachable!("weights did not sum to their own total")
+}
+
+const SURROGATE_START: u32 = 0xD800;
+const SURROGATE_END: u32 = 0xDFFF;
+
+/// Number of valid Unicode scalar values in `start..=end`, excluding the surrogate gap.
+fn scalar_count(start: u32, end: u32) -> u128 {
+    let mut count = u128::from(end - start + 1);
+    let overlap_start = start.max(SURROGATE_START);
+    let overlap_end = end.min(SURROGATE_END);
+    if overlap_start <= overlap_end {
+        count -= u128::from(overlap_end - overlap_start + 1);
+    }
+    count
+}
+
+/// The `index`-th valid Unicode scalar value in `start..=end` (excluding surrogates),
+/// in ascending order.
+fn nth_scalar(start: u32, end: u32, mut index: u128) -> char {
+    let mut val = start;
+    loop {
+        if val >= SURROGATE_START && val <= SURROGATE_END {
+            val = SURROGATE_END + 1;
+            continue;
+        }
+        if index == 0 {
+            return std::char::from_u32(val).expect("scalar_count skips surrogates");
+        }
+        index -= 1;
+        val += 1;
+    }
+}
+
+/// The four UTF-8 encoded-length bands, as inclusive scalar value bounds.
+const UTF8_BANDS: [(u32, u32); 4] = [
+    (0x0000, 0x007F),
+    (0x0080, 0x07FF),
+    (0x0800, 0xFFFF),
+    (0x10000, 0x10FFFF),
+];
+
+/// A unicode class, split into per-UTF-8-length bands so a byte length picks out
+/// exactly the ranges that can produce it.
+struct UnicodeBands {
+    // bands[i] holds the (start, end) ranges for UTF-8 length i + 1, restricted to
+    // that band; counts[i] is the number of scalar values across them.
+    bands: [Vec<(u32, u32)>; 4],
+    counts: [u128; 4],
+}
+
+impl UnicodeBands {
+    fn compile(ranges: &[hir::ClassUnicodeRange]) -> UnicodeBands {
+        let mut bands: [Vec<(u32, u32)>; 4] = [vec![], vec![], vec![], vec![]];
+        let mut counts = [0u128; 4];
+        for range in ranges {
+            let (start, end) = (u32::from(range.start()), u32::from(range.end()));
+            for (i, &(band_start, band_end)) in UTF8_BANDS.iter().enumerate() {
+                let lo = start.max(band_start);
+                let hi = end.min(band_end);
+                if lo <= hi {
+                    counts[i] += scalar_count(lo, hi);
+                    bands[i].push((lo, hi));
+                }
+            }
+        }
+        UnicodeBands { bands, counts }
+    }
+
+    fn sample(&self, byte_len: usize, rng: &mut impl Rng) -> char {
+        let band = &self.bands[byte_len - 1];
+        let mut index = rng.gen_range(0u128..self.counts[byte_len - 1]);
+        for &(lo, hi) in band {
+            let n = scalar_count(lo, hi);
+            if index < n {
+                return nth_scalar(lo, hi, index);
+            }
+            index -= n;
+        }
+        unreachable!("band counts did not sum to their own total")
+    }
+}
+
+struct BytesRanges {
+    ranges: Vec<(u8, u8)>,
+    count: u128,
+}
+
+impl BytesRanges {
+    fn compile(ranges: &[hir::ClassBytesRange]) -> BytesRanges {
+        let ranges: Vec<(u8, u8)> = ranges.iter().map(|r| (r.start(), r.end())).collect();
+        let count = ranges.iter().map(|&(s, e)| u128::from(e - s) + 1).sum();
+        BytesRanges { ranges, count }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> u8 {
+        let mut index = rng.gen_range(0u128..self.count);
+        for &(s, e) in &self.ranges {
+            let n = u128::from(e - s) + 1;
+            if index < n {
+                return s + index as u8;
+            }
+            index -= n;
+        }
+        unreachable!("byte range counts did not sum to their own total")
+    }
+}
+
+enum CountedClass {
+    Unicode(UnicodeBands),
+    Bytes(BytesRanges),
+}
+
+enum CountedKind {
+    /// Matches a zero-length string: `Empty`, anchors (other than end-of-line) and
+    /// word boundaries.
+    EmptyMatch,
+    Literal(Vec<u8>),
+    Class(CountedClass),
+    Group(Box<CountedNode>),
+    Concat(Vec<CountedNode>),
+    Alternation(Vec<CountedNode>),
+    Repetition {
+        child: Box<CountedNode>,
+        min: u32,
+        /// `child` raised to each power from `0` to `max` (self-convolved that many
+        /// times), reused both to build this node's own table and to split a sampled
+        /// length across the chosen number of repeats.
+        powers: Vec<CountTable>,
+    },
+}
+
+struct CountedNode {
+    kind: CountedKind,
+    table: CountTable,
+}
+
+impl CountedNode {
+    fn leaf(kind: CountedKind, table: CountTable) -> CountedNode {
+        CountedNode { kind, table }
+    }
+
+    fn compile(hir: &Hir, max_len: usize, max_repeat: u32) -> Result<CountedNode> {
+        Ok(match *hir.kind() {
+            HirKind::Empty | HirKind::Anchor(hir::Anchor::StartText)
+            | HirKind::Anchor(hir::Anchor::EndText)
+            | HirKind::Anchor(hir::Anchor::StartLine)
+            | HirKind::WordBoundary(_) => {
+                let mut table = zero_table(max_len);
+                table[0] = 1;
+                CountedNode::leaf(CountedKind::EmptyMatch, table)
+            }
+            HirKind::Anchor(hir::Anchor::EndLine) => {
+                let mut table = zero_table(max_len);
+                if max_len >= 1 {
+                    table[1] = 1;
+                }
+                CountedNode::leaf(CountedKind::Literal(b"\n".to_vec()), table)
+            }
+            HirKind::Literal(hir::Literal::Unicode(c)) => {
+                let mut buf = [0u8; 4];
+                let bytes = c.encode_utf8(&mut buf).as_bytes().to_vec();
+                let mut table = zero_table(max_len);
+                if bytes.len() <= max_len {
+                    table[bytes.len()] = 1;
+                }
+                CountedNode::leaf(CountedKind::Literal(bytes), table)
+            }
+            HirKind::Literal(hir::Literal::Byte(b)) => {
+                let mut table = zero_table(max_len);
+                if max_len >= 1 {
+                    table[1] = 1;
+                }
+                CountedNode::leaf(CountedKind::Literal(vec![b]), table)
+            }
+            HirKind::Class(hir::Class::Unicode(ref class)) => {
+                let bands = UnicodeBands::compile(class.ranges());
+                let mut table = zero_table(max_len);
+                for byte_len in 1..=4 {
+                    if byte_len <= max_len {
+                        table[byte_len] = bands.counts[byte_len - 1];
+                    }
+                }
+                CountedNode::leaf(CountedKind::Class(CountedClass::Unicode(bands)), table)
+            }
+            HirKind::Class(hir::Class::Bytes(ref class)) => {
+                let bytes = BytesRanges::compile(class.ranges());
+                let mut table = zero_table(max_len);
+                if max_len >= 1 {
+                    table[1] = bytes.count;
+                }
+                CountedNode::leaf(CountedKind::Class(CountedClass::Bytes(bytes)), table)
+            }
+            HirKind::Group(ref group) => {
+                let child = CountedNode::compile(&group.hir, max_len, max_repeat)?;
+                let table = child.table.clone();
+                CountedNode::leaf(CountedKind::Group(Box::new(child)), table)
+            }
+            HirKind::Concat(ref hirs) => {
+                let children: Vec<CountedNode> = hirs
+                    .iter()
+                    .map(|h| CountedNode::compile(h, max_len, max_repeat))
+                    .collect::<Result<_>>()?;
+                let mut table = zero_table(max_len);
+                table[0] = 1;
+                for child in &children {
+                    table = convolve(&table, &child.table, max_len)?;
+                }
+                CountedNode::leaf(CountedKind::Concat(children), table)
+            }
+            HirKind::Alternation(ref hirs) => {
+                let children: Vec<CountedNode> = hirs
+                    .iter()
+                    .map(|h| CountedNode::compile(h, max_len, max_repeat))
+                    .collect::<Result<_>>()?;
+                let mut table = zero_table(max_len);
+                for child in &children {
+                    for (len, &c) in child.table.iter().enumerate() {
+                        table[len] = checked_add_counts(table[len], c)?;
+                    }
+                }
+                CountedNode::leaf(CountedKind::Alternation(children), table)
+            }
+            HirKind::Repetition(ref repetition) => {
+                let child = CountedNode::compile(&repetition.hir, max_len, max_repeat)?;
+                let (min, max) = repetition_range(&repetition.kind, max_repeat);
+                let mut identity = zero_table(max_len);
+                identity[0] = 1;
+                let mut powers = vec![identity];
+                for k in 1..=max {
+                    let prev = &powers[(k - 1) as usize];
+                    powers.push(convolve(prev, &child.table, max_len)?);
+                }
+                let mut table = zero_table(max_len);
+                for k in min..=max {
+                    let power = &powers[k as usize];
+                    for (len, &c) in power.iter().enumerate() {
+                        table[len] = checked_add_counts(table[len], c)?;
+                    }
+                }
+                CountedNode::leaf(
+                    CountedKind::Repetition { child: Box::new(child), min, powers },
+                    table,
+                )
+            }
+        })
+    }
+
+    /// Emit a string of exactly `target_len` bytes, which must be backed by a nonzero
+    /// entry in `self.table`.
+    fn emit<W: io::Write, R: Rng>(&self, buffer: &mut W, target_len: usize, rng: &mut R) -> Result<()> {
+        match self.kind {
+            CountedKind::EmptyMatch => Ok(()),
+            CountedKind::Literal(ref bytes) => {
+                buffer.write(bytes).chain_err(|| "failed to write literal value")?;
+                Ok(())
+            }
+            CountedKind::Class(CountedClass::Unicode(ref bands)) => {
+                let c = bands.sample(target_len, rng);
+                let mut buf = [0u8; 4];
+                let s = c.encode_utf8(&mut buf);
+                buffer.write(s.as_bytes()).chain_err(|| "failed to write class")?;
+                Ok(())
+            }
+            CountedKind::Class(CountedClass::Bytes(ref bytes)) => {
+                let b = bytes.sample(rng);
+                buffer.write(&[b]).chain_err(|| "failed to write class")?;
+                Ok(())
+            }
+            CountedKind::Group(ref child) => child.emit(buffer, target_len, rng),
+            CountedKind::Concat(ref children) => {
+                // Suffix tables let us weigh "how much of target_len should child i
+                // take" by (child i's count at l) * (everything after it's count at
+                // target_len - l), without re-convolving on every call.
+                let mut suffixes = vec![zero_table(target_len)];
+                suffixes[0][0] = 1;
+                for child in children.iter().rev() {
+                    let next = convolve(suffixes.last().unwrap(), &child.table, target_len)
+                        .chain_err(|| "failed to compute length weights while emitting")?;
+                    suffixes.push(next);
+                }
+                suffixes.reverse();
+
+                let mut remaining = target_len;
+                for (i, child) in children.iter().enumerate() {
+                    let rest = &suffixes[i + 1];
+                    let weights: Vec<u128> = (0..=remaining)
+                        .map(|l| {
+                            let rest_len = remaining - l;
+                            if rest_len < rest.len() {
+                                child.table.get(l).copied().unwrap_or(0) * rest[rest_len]
+                            } else {
+                                0
+                            }
+                        })
+                        .collect();
+                    let l = weighted_index(&weights, rng);
+                    child.emit(buffer, l, rng)?;
+                    remaining -= l;
+                }
+                Ok(())
+            }
+            CountedKind::Alternation(ref children) => {
+                let weights: Vec<u128> = children
+                    .iter()
+                    .map(|c| c.table.get(target_len).copied().unwrap_or(0))
+                    .collect();
+                let i = weighted_index(&weights, rng);
+                children[i].emit(buffer, target_len, rng)
+            }
+            CountedKind::Repetition { ref child, min, ref powers } => {
+                let max = (powers.len() - 1) as u32;
+                let weights: Vec<u128> = (min..=max)
+                    .map(|k| powers[k as usize].get(target_len).copied().unwrap_or(0))
+                    .collect();
+                let k = min + weighted_index(&weights, rng) as u32;
+
+                let mut remaining = target_len;
+                for i in 0..k {
+                    let rest = &powers[(k - i - 1) as usize];
+                    let weights: Vec<u128> = (0..=remaining)
+                        .map(|l| {
+                            let rest_len = remaining - l;
+                            if rest_len < rest.len() {
+                                child.table.get(l).copied().unwrap_or(0) * rest[rest_len]
+                            } else {
+                                0
+                            }
+                        })
+                        .collect();
+                    let l = weighted_index(&weights, rng);
+                    child.emit(buffer, l, rng)?;
+                    remaining -= l;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A regular expression compiled into per-node count tables, ready to sample strings
+/// uniformly at random from the set of all strings (up to `max_len` bytes) it matches.
+///
+/// Compilation is the expensive part; the resulting `UniformGenerator` is reusable and
+/// can be shared across threads (sampling only needs a `&self` and a `Rng`).
+pub struct UniformGenerator {
+    root: CountedNode,
+    max_len: usize,
+}
+
+impl UniformGenerator {
+    /// Compile a regular expression for uniform sampling of strings up to `max_len`
+    /// bytes, capping any unbounded repetition at `DEFAULT_MAX_REPEAT - 1`.
+    pub fn parse(s: &str, max_len: usize) -> Result<UniformGenerator> {
+        Self::new(s, max_len, ::DEFAULT_MAX_REPEAT)
+    }
+
+    /// Compile a regular expression for uniform sampling of strings up to `max_len`
+    /// bytes, capping any unbounded repetition at `max_repeat - 1`.
+    pub fn new(s: &str, max_len: usize, max_repeat: u32) -> Result<UniformGenerator> {
+        let hir = Parser::new().parse(s).chain_err(|| "could not parse expression")?;
+        let root = CountedNode::compile(&hir, max_len, max_repeat)?;
+        Ok(UniformGenerator { root, max_len })
+    }
+
+    /// Fill `buffer` with a string drawn uniformly at random from the set of all
+    /// strings (of at most `max_len` bytes) that the expression matches.
+    pub fn generate<W: io::Write, R: Rng>(&self, buffer: &mut W, rng: &mut R) -> Result<()> {
+        let total = table_sum(&self.root.table);
+        if total == 0 {
+            bail!("no string of length <= {} matches this expression", self.max_len);
+        }
+        let len = weighted_index(&self.root.table, rng);
+        self.root.emit(buffer, len, rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate regex;
+
+    use super::UniformGenerator;
+    use self::regex::Regex;
+    use rand;
+
+    const TEST_N: u64 = 10000;
+    const MAX_LEN: usize = 20;
+
+    fn test_regex(raw: &str) {
+        test_regex_bounded(raw, MAX_LEN);
+    }
+
+    fn test_regex_bounded(raw: &str, max_len: usize) {
+        let gen = UniformGenerator::new(raw, max_len, ::DEFAULT_MAX_REPEAT).unwrap();
+        let rx = Regex::new(raw).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut buffer = vec![];
+
+        for _ in 0..TEST_N {
+            gen.generate(&mut buffer, &mut rng).unwrap();
+            assert!(buffer.len() <= max_len, "Too long: {:?} bytes on {:?}", buffer.len(), raw);
+            match String::from_utf8(buffer.clone()) {
+                Ok(s) => assert!(rx.is_match(&s), "Unexpected: {:?} on {:?}", s, raw),
+                Err(err) => assert!(false, "Error: {:?} {:?}", err, raw),
+            }
+            buffer.clear();
+        }
+    }
+
+    #[test]
+    fn gen_empty() {
+        test_regex(r"");
+    }
+
+    #[test]
+    fn gen_start_end_text() {
+        test_regex(r"^a$");
+    }
+
+    #[test]
+    fn gen_word_boundary() {
+        test_regex(r"\ba\b b");
+    }
+
+    #[test]
+    fn gen_not_word_boundary() {
+        test_regex(r"a\Bb");
+    }
+
+    #[test]
+    fn gen_any() {
+        test_regex(r"(?s).");
+    }
+
+    #[test]
+    fn gen_any_no_newline() {
+        test_regex(r".");
+    }
+
+    #[test]
+    fn gen_literal() {
+        test_regex(r"aBcDe");
+    }
+
+    #[test]
+    fn gen_class() {
+        test_regex(r"[a-zA-Z0-9]");
+    }
+
+    #[test]
+    fn gen_repeat_zero_or_one() {
+        test_regex(r"a?");
+    }
+
+    #[test]
+    fn gen_repeat_zero_or_more() {
+        test_regex(r"a*");
+    }
+
+    #[test]
+    fn gen_repeat_one_or_more() {
+        test_regex(r"a+");
+    }
+
+    #[test]
+    fn gen_repeat_range() {
+        test_regex(r"a{3,8}");
+    }
+
+    #[test]
+    fn gen_repeat_range_exact() {
+        test_regex(r"a{3}");
+    }
+
+    #[test]
+    fn gen_repeat_range_open() {
+        test_regex(r"a{3,}");
+    }
+
+    #[test]
+    fn gen_group() {
+        test_regex(r"(abcde)");
+    }
+
+    #[test]
+    fn gen_concat() {
+        test_regex(r"a?b?");
+    }
+
+    #[test]
+    fn gen_alternate() {
+        test_regex(r"a|b");
+    }
+
+    #[test]
+    fn gen_unicode_classes() {
+        test_regex(r"\p{L}");
+        test_regex(r"\p{Greek}");
+    }
+
+    #[test]
+    fn gen_complex() {
+        test_regex(r"^(\p{Greek}\P{Greek})(?:\d{3,6})$");
+    }
+
+    /// `max_len` values right at a repetition's min/max bounds must still produce
+    /// exactly the lengths the table says are possible, not silently truncate or pad.
+    #[test]
+    fn gen_boundary_max_len_equals_min() {
+        // `a{3,8}` can only be satisfied down to 3 bytes; a max_len of exactly 3
+        // forces every draw to the shortest possible expansion.
+        test_regex_bounded(r"a{3,8}", 3);
+    }
+
+    #[test]
+    fn gen_boundary_max_len_mid_range() {
+        // A max_len strictly between a repetition's min and max should still only
+        // ever produce lengths within `3..=5`, never exceeding the cap.
+        test_regex_bounded(r"a{3,8}", 5);
+    }
+
+    #[test]
+    fn gen_boundary_max_len_zero() {
+        test_regex_bounded(r"a?", 0);
+    }
+
+    /// `\w` is large enough (tens of thousands of code points) that `\w{1,22}` puts a
+    /// count well past `u128::MAX` in the table before compilation finishes. This is a
+    /// realistic "token up to length N" pattern, not a pathological one, so it must be
+    /// reported as an error rather than panicking or silently wrapping.
+    #[test]
+    fn gen_realistic_repeat_reports_overflow_instead_of_panicking() {
+        assert!(UniformGenerator::new(r"\w{1,22}", 22, ::DEFAULT_MAX_REPEAT).is_err());
+    }
+}